@@ -6,12 +6,72 @@ use crate::AdamState;
 use crate::AdamParams;
 use itertools::Itertools as _;
 
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone)]
+/// The direction in which the driver should move the state's vector: toward
+/// the highest-scoring champion (`Maximize`) or the lowest-scoring one
+/// (`Minimize`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Objective {
+    Minimize,
+    Maximize,
+}
+
+impl Default for Objective {
+    fn default() -> Self {
+        Objective::Maximize
+    }
+}
+
+/// How the per-coordinate gradient is estimated from the scored population.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GradientEstimator {
+    /// Ordinary least-squares slope of each coordinate against the score,
+    /// regressed against the champion (`generate_gradient_at_point`). Fast,
+    /// but a single catastrophic score can dominate the estimate.
+    LeastSquares,
+    /// Theil-Sen estimator: the median of all pairwise slopes between
+    /// population members, per coordinate. Breaks down only once more than
+    /// ~29% of the population members are bad, at the cost of `O(n^2)` work
+    /// per coordinate.
+    TheilSen,
+}
+
+impl Default for GradientEstimator {
+    fn default() -> Self {
+        GradientEstimator::LeastSquares
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdamDriver {
     starting_population_size: usize,
     sustain_population_size: usize,
+    objective: Objective,
+    gradient_estimator: GradientEstimator,
+
+    /// how many of the previous generation's top-scoring vectors survive
+    /// unchanged into the next generation
+    elite_count: usize,
+    /// how many tournament winners (see `tournament_size`) survive into the
+    /// next generation
+    tournament_count: usize,
+    /// how many random candidates are drawn per tournament
+    tournament_size: usize,
+
+    /// how many bootstrap resamples to draw when estimating the gradient's
+    /// standard error; 0 disables the bootstrap entirely
+    nresamples: usize,
+    /// whether to shrink each gradient component toward zero by
+    /// `|slope| / (|slope| + stderr)` before handing it to `state.update`
+    bootstrap_shrinkage: bool,
+    /// the per-coordinate standard error computed by the most recent
+    /// bootstrap, empty if no bootstrap has run yet; disabling the bootstrap
+    /// does not clear it
+    last_gradient_stderr: Vec<FLOAT>,
 
     vectors: Vec<Vec<FLOAT>>,
 }
@@ -20,6 +80,7 @@ impl AdamDriver {
     pub fn new<R>(
         starting_population_size: usize,
         sustain_population_size: usize,
+        objective: Objective,
         state: &AdamState,
         rng: &mut R,
     ) -> AdamDriver
@@ -36,10 +97,124 @@ impl AdamDriver {
         AdamDriver {
             starting_population_size,
             sustain_population_size,
+            objective,
+            gradient_estimator: GradientEstimator::default(),
+            elite_count: 0,
+            tournament_count: 0,
+            tournament_size: 0,
+            nresamples: 0,
+            bootstrap_shrinkage: false,
+            last_gradient_stderr: vec![],
             vectors,
         }
     }
 
+    /// Rebuilds a driver from its already-known parts (e.g. fields pulled
+    /// out of a deserialized checkpoint) instead of drawing a fresh
+    /// `StandardNormal` population the way `new` does. `rng` is accepted
+    /// for symmetry with `new` and future resampling, but is not consulted
+    /// here since `vectors` is taken as-is.
+    pub fn from_parts<R>(
+        starting_population_size: usize,
+        sustain_population_size: usize,
+        objective: Objective,
+        vectors: Vec<Vec<FLOAT>>,
+        _rng: &mut R,
+    ) -> AdamDriver
+    where R: Rng {
+        AdamDriver {
+            starting_population_size,
+            sustain_population_size,
+            objective,
+            gradient_estimator: GradientEstimator::default(),
+            elite_count: 0,
+            tournament_count: 0,
+            tournament_size: 0,
+            nresamples: 0,
+            bootstrap_shrinkage: false,
+            last_gradient_stderr: vec![],
+            vectors,
+        }
+    }
+
+    /// Resumes a run from a driver deserialized from a checkpoint. Since
+    /// `AdamDriver` is itself `Serialize`/`Deserialize`, this is a thin
+    /// pass-through; it exists so a caller resuming a checkpointed
+    /// `(AdamDriver, AdamState)` pair doesn't need to know that resuming
+    /// requires no extra work, only a fresh `rng` to carry on with.
+    pub fn resume<R>(driver: AdamDriver, _rng: &mut R) -> AdamDriver
+    where R: Rng {
+        driver
+    }
+
+    pub fn objective(&self) -> Objective {
+        self.objective
+    }
+
+    pub fn gradient_estimator(&self) -> GradientEstimator {
+        self.gradient_estimator
+    }
+
+    pub fn set_gradient_estimator(&mut self, gradient_estimator: GradientEstimator) {
+        self.gradient_estimator = gradient_estimator;
+    }
+
+    pub fn elite_count(&self) -> usize {
+        self.elite_count
+    }
+
+    pub fn tournament_count(&self) -> usize {
+        self.tournament_count
+    }
+
+    pub fn tournament_size(&self) -> usize {
+        self.tournament_size
+    }
+
+    /// Configures elitism/tournament survivor seeding for `resample_vectors`.
+    /// `elite_count` vectors carry over unchanged as the top scorers of the
+    /// previous generation; `tournament_count` more carry over as the
+    /// winners of as many tournaments, each contested by `tournament_size`
+    /// random members of the previous generation. Both default to 0 (pure
+    /// Gaussian resampling).
+    pub fn set_tournament_selection(
+        &mut self,
+        elite_count: usize,
+        tournament_count: usize,
+        tournament_size: usize,
+    ) {
+        self.elite_count = elite_count;
+        self.tournament_count = tournament_count;
+        self.tournament_size = tournament_size;
+    }
+
+    /// Configures the gradient bootstrap. With `nresamples > 0`,
+    /// `update_vectors_and_state` draws that many resamples (with
+    /// replacement) of the population to estimate a per-coordinate standard
+    /// error for the gradient, retrievable via `gradient_stderr`. If
+    /// `shrinkage` is set, each gradient component is additionally shrunk
+    /// toward zero by `|slope| / (|slope| + stderr)` before the Adam step,
+    /// so coordinates whose sign isn't robust across resamples move less.
+    pub fn set_bootstrap(&mut self, nresamples: usize, shrinkage: bool) {
+        self.nresamples = nresamples;
+        self.bootstrap_shrinkage = shrinkage;
+    }
+
+    pub fn nresamples(&self) -> usize {
+        self.nresamples
+    }
+
+    pub fn bootstrap_shrinkage(&self) -> bool {
+        self.bootstrap_shrinkage
+    }
+
+    /// The per-coordinate gradient standard error computed by the most
+    /// recent bootstrap. Empty if no bootstrap has run yet; disabling the
+    /// bootstrap with `set_bootstrap(0, _)` keeps the last result.
+    pub fn gradient_stderr(&self) -> &[FLOAT] {
+        &*self.last_gradient_stderr
+    }
+
     pub fn vectors(&self) -> &[Vec<FLOAT>] {
         &*self.vectors
     }
@@ -48,13 +223,15 @@ impl AdamDriver {
         self.vectors.iter_mut().map(|v| &mut **v).collect::<Vec<_>>()
     }
 
+    #[cfg_attr(feature = "rayon", allow(unused_variables))]
     pub fn resample_vectors<R>(
         &mut self,
         state: &AdamState,
         params: &AdamParams,
         count: usize,
+        scores: &[FLOAT],
         rng: &mut R,
-    ) 
+    )
     where R: Rng {
         // the mean is the vector while the variance is the v_hat.
         let mean = state.vector().to_vec();
@@ -66,9 +243,32 @@ impl AdamDriver {
         println!("M:\t{:+.04} {:+.04}", mean[0], mean[1]);
         println!("S:\t{:+.04} {:+.04}", stdev[0], stdev[1]);
 
+        // seed the next generation with survivors from the previous one
+        // before falling back to Gaussian resampling for the rest
+        let elites = select_elites(
+            &self.vectors,
+            scores,
+            self.objective,
+            self.elite_count,
+        );
+        let tournament_winners = select_tournament_winners(
+            &self.vectors,
+            scores,
+            self.objective,
+            self.tournament_count,
+            self.tournament_size,
+            rng,
+        );
+
         self.vectors = vec![mean.clone()];
-        self.vectors.reserve(count - 1);
+        self.vectors.extend(elites);
+        self.vectors.extend(tournament_winners);
+
+        let survivors = self.vectors.len();
+        let remaining = count.saturating_sub(survivors);
+        self.vectors.reserve(remaining);
 
+        #[cfg(not(feature = "rayon"))]
         core::iter::repeat_with(|| {
             // sample given the mean and standard deviation
             mean.iter()
@@ -82,9 +282,27 @@ impl AdamDriver {
                 // collect into a vector
                 .collect::<Vec<_>>()
         })
-            // take up to how many is demanded minus one (since that's reserved
-            // for the mean)
-            .take(count - 1)
+            // take up to how many is still demanded after the survivors
+            .take(remaining)
+            .for_each(|vec| self.vectors.push(vec));
+
+        // each output vector only depends on `mean`/`stdev`, so with the
+        // `rayon` feature we sample them independently in parallel, each
+        // with its own thread-local RNG
+        #[cfg(feature = "rayon")]
+        (0 .. remaining)
+            .into_par_iter()
+            .map(|_| {
+                let mut rng = rand::thread_rng();
+                mean.iter()
+                    .zip(stdev.iter())
+                    .take(mean.len())
+                    .map(|(m, std)| Normal::new(*m, *std).unwrap())
+                    .map(|normal| normal.sample(&mut rng))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
             .for_each(|vec| self.vectors.push(vec));
     }
 
@@ -111,8 +329,12 @@ impl AdamDriver {
                 .zip(scores.iter())
                 .enumerate()
                 .map(|(i, (v, s))| (i, v, s))
-                // find the vector with the highest score
-                .max_by(|(_, _, a), (_, _, b)| a.partial_cmp(b).unwrap())
+                // find the vector with the best score for our objective
+                // (highest when maximizing, lowest when minimizing)
+                .max_by(|(_, _, a), (_, _, b)| match self.objective {
+                    Objective::Maximize => a.partial_cmp(b).unwrap(),
+                    Objective::Minimize => b.partial_cmp(a).unwrap(),
+                })
                 // take only the vector but also record the index of the champ
                 .map(|(i, vector, _)| {
                     champ_index = i;
@@ -135,14 +357,106 @@ impl AdamDriver {
         // its corresponding score
         // the state.vector is located in our states: it's always on the first
         // element. likewise, it should be the same for the score.
-        let (vfirst, vrest) = self.vectors.split_first().unwrap();
-        let (sfirst, srest) = scores.split_first().unwrap();
-        let gradient = generate_gradient_at_point(
-            &**vfirst,
-            *sfirst,
-            vrest.iter().map(|v| &**v),
-            srest.iter().cloned(),
-        );
+        let mut gradient = match self.gradient_estimator {
+            GradientEstimator::LeastSquares => {
+                let (vfirst, vrest) = self.vectors.split_first().unwrap();
+                let (sfirst, srest) = scores.split_first().unwrap();
+                generate_gradient_at_point(
+                    &**vfirst,
+                    *sfirst,
+                    vrest.iter().map(|v| &**v),
+                    srest.iter().cloned(),
+                )
+            },
+            GradientEstimator::TheilSen => {
+                generate_gradient_theil_sen(&self.vectors, &scores)
+            },
+        };
+
+        // Adam always descends. when minimizing, the regressed slope already
+        // points toward higher scores, so flip it to point toward lower
+        // ones.
+        if self.objective == Objective::Minimize {
+            for g in gradient.iter_mut() {
+                *g = -*g;
+            }
+        }
+
+        // bootstrap the gradient's per-coordinate standard error by
+        // re-regressing over resamples (with replacement) of the
+        // population, so a gradient whose sign isn't robust across
+        // resamples can be shrunk toward zero before it moves the state.
+        // skipped for populations too small to resample from.
+        if self.nresamples > 0 && self.vectors.len() > 1 {
+            let (vfirst, vrest) = self.vectors.split_first().unwrap();
+            let (sfirst, srest) = scores.split_first().unwrap();
+
+            let mut per_coord_samples = (0 .. gradient.len())
+                .map(|_| Vec::with_capacity(self.nresamples))
+                .collect::<Vec<_>>();
+
+            for _ in 0 .. self.nresamples {
+                // resample using the same estimator the driver is
+                // configured with, so the reported stderr corresponds to
+                // the gradient actually handed to `state.update`
+                let resample_gradient = match self.gradient_estimator {
+                    GradientEstimator::LeastSquares => {
+                        let resample_indices = (0 .. vrest.len())
+                            .map(|_| rng.gen_range(0 .. vrest.len()))
+                            .collect::<Vec<_>>();
+
+                        generate_gradient_at_point(
+                            &**vfirst,
+                            *sfirst,
+                            resample_indices.iter().map(|&i| &*vrest[i]),
+                            resample_indices.iter().map(|&i| srest[i]),
+                        )
+                    },
+                    GradientEstimator::TheilSen => {
+                        let resample_indices = (0 .. self.vectors.len())
+                            .map(|_| rng.gen_range(0 .. self.vectors.len()))
+                            .collect::<Vec<_>>();
+
+                        let resample_vectors = resample_indices.iter()
+                            .map(|&i| self.vectors[i].clone())
+                            .collect::<Vec<_>>();
+                        let resample_scores = resample_indices.iter()
+                            .map(|&i| scores[i])
+                            .collect::<Vec<_>>();
+
+                        generate_gradient_theil_sen(&resample_vectors, &resample_scores)
+                    },
+                };
+
+                for (samples, g) in per_coord_samples.iter_mut().zip(resample_gradient) {
+                    samples.push(g);
+                }
+            }
+
+            let stderr = per_coord_samples.iter()
+                .map(|samples| {
+                    let n = samples.len() as FLOAT;
+                    let mean = samples.iter().sum::<FLOAT>() / n;
+                    let variance = samples.iter()
+                        .map(|s| (s - mean).powi(2))
+                        .sum::<FLOAT>() / n;
+                    variance.sqrt()
+                })
+                .collect::<Vec<_>>();
+
+            if self.bootstrap_shrinkage {
+                for (g, se) in gradient.iter_mut().zip(stderr.iter()) {
+                    let denom = g.abs() + se;
+                    let shrink = match denom > 0. {
+                        true => g.abs() / denom,
+                        false => 1.,
+                    };
+                    *g *= shrink;
+                }
+            }
+
+            self.last_gradient_stderr = stderr;
+        }
 
         // update the state
         state.update(&*gradient, params).unwrap();
@@ -152,11 +466,71 @@ impl AdamDriver {
             &state,
             params,
             self.sustain_population_size,
+            &scores,
             rng
         );
     }
 }
 
+// true if `a` should be preferred over `b` under the given objective
+fn is_better(objective: Objective, a: FLOAT, b: FLOAT) -> bool {
+    match objective {
+        Objective::Maximize => a > b,
+        Objective::Minimize => a < b,
+    }
+}
+
+/// Returns the `elite_count` top-scoring vectors from `vectors`, unchanged.
+fn select_elites(
+    vectors: &[Vec<FLOAT>],
+    scores: &[FLOAT],
+    objective: Objective,
+    elite_count: usize,
+) -> Vec<Vec<FLOAT>>
+{
+    let mut ranked = vectors.iter().zip(scores.iter()).collect::<Vec<_>>();
+    ranked.sort_by(|(_, a), (_, b)| match objective {
+        Objective::Maximize => b.partial_cmp(a).unwrap(),
+        Objective::Minimize => a.partial_cmp(b).unwrap(),
+    });
+
+    ranked.into_iter()
+        .take(elite_count)
+        .map(|(vector, _)| vector.clone())
+        .collect::<Vec<_>>()
+}
+
+/// Runs `tournament_count` tournaments, each contested by `tournament_size`
+/// random members of `vectors`, and returns the winner of each.
+fn select_tournament_winners<R>(
+    vectors: &[Vec<FLOAT>],
+    scores: &[FLOAT],
+    objective: Objective,
+    tournament_count: usize,
+    tournament_size: usize,
+    rng: &mut R,
+) -> Vec<Vec<FLOAT>>
+where R: Rng {
+    if tournament_size == 0 || vectors.is_empty() {
+        return vec![];
+    }
+
+    (0 .. tournament_count)
+        .map(|_| {
+            let mut champ = rng.gen_range(0 .. vectors.len());
+
+            for _ in 1 .. tournament_size {
+                let contender = rng.gen_range(0 .. vectors.len());
+                if is_better(objective, scores[contender], scores[champ]) {
+                    champ = contender;
+                }
+            }
+
+            vectors[champ].clone()
+        })
+        .collect::<Vec<_>>()
+}
+
 fn generate_gradient_at_point<'a>(
     center: &[FLOAT],
     center_score: FLOAT,
@@ -164,27 +538,75 @@ fn generate_gradient_at_point<'a>(
     scores: impl Iterator<Item = FLOAT>,
 ) -> Vec<FLOAT>
 {
-    let mut x_diff_y_diff = vec![0.; center.len()];
-    let mut x_diff_sq = vec![0.; center.len()];
-
-    // iterate through the vectors
-    for (vector, score) in vectors.zip(scores) {
-        let iter = vector
-            .iter()
-            .zip_eq(x_diff_y_diff.iter_mut())
-            .zip_eq(x_diff_sq.iter_mut())
-            .zip_eq(center.iter())
-            .map(|(((a, b), c), d)| (a, b, c, d));
-
-        // for each of the parameter, increment the value of the numerator and
-        // the denominator for the line of best fit
-        let dy = score - center_score;
-        for (param, sdxdy, sdxdx, center) in iter {
-            let dx = *param - *center;
-            *sdxdy += dx * dy;
-            *sdxdx += dx.powi(2);
+    let d = center.len();
+
+    #[cfg(not(feature = "rayon"))]
+    let (x_diff_y_diff, x_diff_sq) = {
+        let mut x_diff_y_diff = vec![0.; d];
+        let mut x_diff_sq = vec![0.; d];
+
+        // iterate through the vectors
+        for (vector, score) in vectors.zip(scores) {
+            let iter = vector
+                .iter()
+                .zip_eq(x_diff_y_diff.iter_mut())
+                .zip_eq(x_diff_sq.iter_mut())
+                .zip_eq(center.iter())
+                .map(|(((a, b), c), d)| (a, b, c, d));
+
+            // for each of the parameter, increment the value of the
+            // numerator and the denominator for the line of best fit
+            let dy = score - center_score;
+            for (param, sdxdy, sdxdx, center) in iter {
+                let dx = *param - *center;
+                *sdxdy += dx * dy;
+                *sdxdx += dx.powi(2);
+            }
         }
-    }
+
+        (x_diff_y_diff, x_diff_sq)
+    };
+
+    // with the `rayon` feature, fold the same numerator/denominator
+    // accumulation across population members in parallel, then reduce the
+    // per-thread partials by element-wise addition
+    #[cfg(feature = "rayon")]
+    let (x_diff_y_diff, x_diff_sq) = vectors
+        .zip(scores)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .fold(
+            || (vec![0.; d], vec![0.; d]),
+            |(mut sdxdy, mut sdxdx), (vector, score)| {
+                let dy = score - center_score;
+                let iter = vector
+                    .iter()
+                    .zip_eq(sdxdy.iter_mut())
+                    .zip_eq(sdxdx.iter_mut())
+                    .zip_eq(center.iter())
+                    .map(|(((a, b), c), d)| (a, b, c, d));
+
+                for (param, sdxdy, sdxdx, center) in iter {
+                    let dx = *param - *center;
+                    *sdxdy += dx * dy;
+                    *sdxdx += dx.powi(2);
+                }
+
+                (sdxdy, sdxdx)
+            },
+        )
+        .reduce(
+            || (vec![0.; d], vec![0.; d]),
+            |(mut a_dxdy, mut a_dxdx), (b_dxdy, b_dxdx)| {
+                for (a, b) in a_dxdy.iter_mut().zip_eq(b_dxdy.iter()) {
+                    *a += b;
+                }
+                for (a, b) in a_dxdx.iter_mut().zip_eq(b_dxdx.iter()) {
+                    *a += b;
+                }
+                (a_dxdy, a_dxdx)
+            },
+        );
 
     // perform piecewise division
     x_diff_y_diff.into_iter()
@@ -192,3 +614,47 @@ fn generate_gradient_at_point<'a>(
         .map(|(sdxdy, sdxdx)| sdxdy / sdxdx)
         .collect::<Vec<_>>()
 }
+
+// a denominator below this is considered too close to zero to produce a
+// usable slope, and the pair is skipped
+const THEIL_SEN_MIN_DENOM: FLOAT = 1e-9;
+
+/// Robust, outlier-resistant alternative to `generate_gradient_at_point`:
+/// for each coordinate, takes the median of the pairwise slopes between
+/// every pair of population members (instead of a single least-squares
+/// regression against the champion). This is `O(n^2)` per coordinate but
+/// tolerates roughly 29% of the population being wildly wrong.
+fn generate_gradient_theil_sen(
+    vectors: &[Vec<FLOAT>],
+    scores: &[FLOAT],
+) -> Vec<FLOAT>
+{
+    let d = vectors.first().map(|v| v.len()).unwrap_or(0);
+    let mut gradient = vec![0.; d];
+
+    for k in 0..d {
+        let mut slopes = Vec::with_capacity(vectors.len() * vectors.len() / 2);
+
+        for i in 0 .. vectors.len() {
+            for j in (i + 1) .. vectors.len() {
+                let dx = vectors[j][k] - vectors[i][k];
+                if dx.abs() < THEIL_SEN_MIN_DENOM {
+                    continue;
+                }
+
+                let dy = scores[j] - scores[i];
+                slopes.push(dy / dx);
+            }
+        }
+
+        slopes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        gradient[k] = match slopes.len() {
+            0 => 0.,
+            len if len % 2 == 1 => slopes[len / 2],
+            len => (slopes[len / 2 - 1] + slopes[len / 2]) / 2.,
+        };
+    }
+
+    gradient
+}
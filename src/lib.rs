@@ -7,7 +7,7 @@ mod driver;
 
 pub use params::AdamParams;
 pub use state::AdamState;
-pub use driver::AdamDriver;
+pub use driver::{AdamDriver, GradientEstimator, Objective};
 
 #[cfg(feature = "FLOAT32")]
 type FLOAT = f32;
@@ -8,6 +8,8 @@ pub struct AdamParams {
     pub epsilon: FLOAT,
     pub beta_1:  FLOAT,
     pub beta_2:  FLOAT,
+    pub amsgrad: bool,
+    pub weight_decay: FLOAT,
 }
 
 impl Default for AdamParams {
@@ -17,6 +19,8 @@ impl Default for AdamParams {
             epsilon: 0.00000001,
             beta_1:  0.9,
             beta_2:  0.999,
+            amsgrad: false,
+            weight_decay: 0.0,
         }
     }
 }
@@ -10,6 +10,7 @@ use thiserror::Error;
 pub struct AdamState {
     m: Vec<FLOAT>, // has a length of d
     v: Vec<FLOAT>, // has a length of d
+    v_max: Vec<FLOAT>, // has a length of d, only used in AMSGrad mode
     pub t: i32,
     vector: Vec<FLOAT>, // has a length of d
 }
@@ -23,6 +24,7 @@ impl AdamState {
         AdamState {
             m:       vec![0.; param_count],
             v:       vec![0.; param_count],
+            v_max:   vec![0.; param_count],
             t:       0,
             vector: vec![0.; param_count],
         }
@@ -36,6 +38,7 @@ impl AdamState {
         let count = self.vector_len();
         self.m = vec![0.; count];
         self.v = vec![0.; count];
+        self.v_max = vec![0.; count];
         self.t = 0;
     }
 
@@ -55,6 +58,10 @@ impl AdamState {
         &self.v
     }
 
+    pub fn v_max(&self) -> &[FLOAT] {
+        &self.v_max
+    }
+
     pub fn t(&self) -> i32 {
         self.t
     }
@@ -67,6 +74,10 @@ impl AdamState {
         get_bias_corrected_moment_estimate(&self.v, params.beta_2, self.t)
     }
 
+    pub fn v_max_hat(&self, params: &AdamParams) -> Vec<FLOAT> {
+        get_bias_corrected_moment_estimate(&self.v_max, params.beta_2, self.t)
+    }
+
     pub fn update(
         &mut self,
         gradient: &[FLOAT],
@@ -93,13 +104,27 @@ impl AdamState {
             false,
         );
 
+        if params.amsgrad {
+            for (v_max_val, v_val) in self.v_max.iter_mut().zip(self.v.iter()) {
+                *v_max_val = v_max_val.max(*v_val);
+            }
+        }
+
         let m_hat = self.m_hat(params);
-        let v_hat = self.v_hat(params);
+        let v_used_hat = match params.amsgrad {
+            true => self.v_max_hat(params),
+            false => self.v_hat(params),
+        };
 
         // update the current vector
-        let iter = self.vector.iter_mut().zip(m_hat.iter()).zip(v_hat.iter());
+        let iter = self.vector.iter_mut().zip(m_hat.iter()).zip(v_used_hat.iter());
         for ((param, mean), var) in iter {
             *param -= params.alpha * *mean / (var.sqrt() + params.epsilon);
+
+            // decoupled weight decay (AdamW): applied directly to the
+            // parameter rather than mixed into the gradient, so it doesn't
+            // skew the moment estimates
+            *param -= params.alpha * params.weight_decay * *param;
         }
 
         Ok(())